@@ -1,15 +1,244 @@
-use rusqlite::Connection;
+use base64::Engine;
+use r2d2::{CustomizeConnection, Pool};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, Row};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use thiserror::Error;
 
+/// SQLCipher PRAGMA parameters needed to open a given WeChat database.
+///
+/// WeChat 3.x ships SQLCipher 3 databases (`page_size = 1024`, `kdf_iter =
+/// 64000`, SHA1-based KDF/HMAC), while WeChat 4.x moved to SQLCipher 4
+/// defaults (`page_size = 4096`, `kdf_iter = 256000`, SHA512-based). Both
+/// need to be reachable from the same codebase.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CipherParams {
+    pub compatibility: i32,
+    pub page_size: i32,
+    pub kdf_iter: i32,
+    pub hmac_algorithm: String,
+    pub kdf_algorithm: String,
+    pub plaintext_header_size: i32,
+}
+
+impl Default for CipherParams {
+    fn default() -> Self {
+        Self {
+            compatibility: 3,
+            page_size: 1024,
+            kdf_iter: 64000,
+            hmac_algorithm: "HMAC_SHA1".to_string(),
+            kdf_algorithm: "PBKDF2_HMAC_SHA1".to_string(),
+            plaintext_header_size: 0,
+        }
+    }
+}
+
+/// Per-connection tuning knobs for [`DatabaseManager::connect_database`].
+///
+/// Exposed to the frontend so it can tailor the cipher parameters and
+/// locking behaviour to a specific WeChat database instead of relying on
+/// the SQLCipher 3 defaults baked into [`CipherParams::default`].
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ConnectionOptions {
+    pub busy_timeout: Option<Duration>,
+    pub enable_wal: bool,
+    pub synchronous: Option<String>,
+    pub cipher: CipherParams,
+}
+
+impl ConnectionOptions {
+    /// Applies this connection's PRAGMAs to a freshly opened, keyed
+    /// connection. Order matters: cipher PRAGMAs must be set before the
+    /// first real query touches the database. Returns a plain
+    /// `rusqlite::Result` so it can double as an r2d2
+    /// [`r2d2::CustomizeConnection`] hook (see [`CipherCustomizer`]).
+    fn apply(&self, conn: &Connection, key: &str) -> rusqlite::Result<()> {
+        let execute_pragma = |pragma: &str| -> rusqlite::Result<()> {
+            match conn.execute(pragma, []) {
+                Ok(_) => Ok(()),
+                Err(rusqlite::Error::ExecuteReturnedResults) => {
+                    // Some PRAGMA statements might return results, consume them
+                    let mut stmt = conn.prepare(pragma)?;
+                    let mut rows = stmt.query([])?;
+                    while rows.next()?.is_some() {
+                        // Consume any results
+                    }
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            }
+        };
+
+        // All cipher parameters are used to *derive* the key, so every one
+        // of them must be set before `PRAGMA key` runs — setting them
+        // afterward is silently a no-op and the candidate looks identical
+        // to whatever the connection's prior defaults were.
+        execute_pragma(&format!("PRAGMA cipher_compatibility = {}", self.cipher.compatibility))?;
+        execute_pragma(&format!("PRAGMA cipher_page_size = {}", self.cipher.page_size))?;
+        execute_pragma(&format!("PRAGMA kdf_iter = {}", self.cipher.kdf_iter))?;
+        execute_pragma(&format!("PRAGMA cipher_hmac_algorithm = {}", self.cipher.hmac_algorithm))?;
+        execute_pragma(&format!("PRAGMA cipher_kdf_algorithm = {}", self.cipher.kdf_algorithm))?;
+        if self.cipher.plaintext_header_size > 0 {
+            execute_pragma(&format!("PRAGMA cipher_plaintext_header_size = {}", self.cipher.plaintext_header_size))?;
+        }
+        execute_pragma(&format!("PRAGMA key = \"{}\"", key))?;
+
+        if let Some(timeout) = self.busy_timeout {
+            conn.busy_timeout(timeout)?;
+        }
+        if self.enable_wal {
+            execute_pragma("PRAGMA journal_mode = WAL")?;
+        }
+        if let Some(sync) = &self.synchronous {
+            execute_pragma(&format!("PRAGMA synchronous = {}", sync))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A single traced statement, recorded by [`CipherCustomizer`]'s trace
+/// callback into [`DatabaseManager`]'s per-database ring buffer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryHistoryEntry {
+    /// Process-unique, monotonically increasing id assigned by the trace
+    /// callback. Lets [`DatabaseManager::record_row_count`] find the exact
+    /// entry a statement produced instead of assuming it's still the last
+    /// one in the buffer — concurrent `execute_query` calls against the
+    /// same database share this ring buffer, so another thread's entry can
+    /// land on top of this one before row_count is filled in.
+    pub id: u64,
+    pub db_id: String,
+    pub sql: String,
+    pub duration_ms: u64,
+    pub timestamp: u64,
+    /// Not populated by the trace callback itself (sqlite's trace API
+    /// doesn't expose row counts); [`DatabaseManager::execute_query`] fills
+    /// this in on the matching entry once it knows how many rows came back.
+    pub row_count: Option<i64>,
+    pub read_only: bool,
+}
+
+/// Max entries kept per database in the query history ring buffer.
+const QUERY_HISTORY_CAPACITY: usize = 200;
+
+/// Source of [`QueryHistoryEntry::id`] values, shared process-wide since the
+/// ring buffers themselves are per-database but ids only need to be unique,
+/// not contiguous per database.
+static NEXT_HISTORY_ENTRY_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+thread_local! {
+    /// The id of the last history entry the trace callback created *on this
+    /// thread*. SQLite invokes the trace callback synchronously on the
+    /// thread executing the statement, so this is always the entry
+    /// `execute_query`'s own `run_query` call just produced — safe to read
+    /// even though other threads are concurrently appending their own
+    /// entries to the same shared ring buffer.
+    static LAST_TRACED_ENTRY_ID: std::cell::Cell<Option<u64>> = std::cell::Cell::new(None);
+}
+
+/// Best-effort classification of a SQL statement as read-only, used both
+/// to tag history entries and to let `execute_query` reject writes when
+/// the caller asks for `read_only` enforcement.
+fn is_read_only_sql(sql: &str) -> bool {
+    let trimmed = sql.trim_start();
+    let upper_full = trimmed.to_ascii_uppercase();
+    let prefix: String = trimmed.chars().take(16).collect::<String>().to_ascii_uppercase();
+
+    if prefix.starts_with("PRAGMA") {
+        // Query-form PRAGMAs (`PRAGMA table_info(x)`, `PRAGMA user_version`)
+        // are read-only; assignment-form ones (`PRAGMA user_version = 5`,
+        // `PRAGMA journal_mode = DELETE`) mutate database state.
+        return !trimmed.contains('=');
+    }
+
+    if prefix.starts_with("SELECT") || prefix.starts_with("EXPLAIN") {
+        return true;
+    }
+
+    if prefix.starts_with("WITH") {
+        // A CTE is read-only only if it terminates in a SELECT rather
+        // than a write statement (`WITH ... INSERT/UPDATE/DELETE ...`).
+        const WRITE_KEYWORDS: [&str; 5] = ["INSERT", "UPDATE", "DELETE", "REPLACE", "DROP"];
+        return !WRITE_KEYWORDS.iter().any(|kw| upper_full.contains(kw));
+    }
+
+    false
+}
+
+/// r2d2 connection hook that applies the cipher PRAGMAs (and WAL/busy
+/// timeout/synchronous settings) to every raw connection the pool opens,
+/// since SQLCipher state is per-connection and `r2d2_sqlite` otherwise
+/// hands back a connection that was never keyed. Also wires up SQL tracing
+/// so every statement run on the connection is appended to the shared
+/// query history ring buffer.
+#[derive(Debug)]
+struct CipherCustomizer {
+    key: String,
+    opts: ConnectionOptions,
+    db_id: String,
+    history: Arc<Mutex<VecDeque<QueryHistoryEntry>>>,
+}
+
+impl CustomizeConnection<Connection, rusqlite::Error> for CipherCustomizer {
+    fn on_acquire(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        self.opts.apply(conn, &self.key)?;
+        conn.trace(Some(make_trace_callback(self.db_id.clone(), Arc::clone(&self.history))));
+        Ok(())
+    }
+}
+
+/// Builds the trace closure installed on every pooled connection, recording
+/// each statement run on it into `db_id`'s query history ring buffer.
+/// Pulled out of [`CipherCustomizer::on_acquire`] so [`DatabaseManager`] can
+/// also re-install it after temporarily disabling tracing (see
+/// [`DatabaseManager::federated_query`], which must not trace the
+/// `ATTACH ... KEY ?` statements issued by
+/// [`DatabaseManager::attach_database`]).
+fn make_trace_callback(
+    db_id: String,
+    history: Arc<Mutex<VecDeque<QueryHistoryEntry>>>,
+) -> Box<dyn Fn(&str, Duration) + Send + Sync> {
+    Box::new(move |sql: &str, duration: Duration| {
+        let id = NEXT_HISTORY_ENTRY_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let entry = QueryHistoryEntry {
+            id,
+            db_id: db_id.clone(),
+            sql: sql.to_string(),
+            duration_ms: duration.as_millis() as u64,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            row_count: None,
+            read_only: is_read_only_sql(sql),
+        };
+        if let Ok(mut buf) = history.lock() {
+            if buf.len() >= QUERY_HISTORY_CAPACITY {
+                buf.pop_front();
+            }
+            buf.push_back(entry);
+        }
+        LAST_TRACED_ENTRY_ID.with(|cell| cell.set(Some(id)));
+    })
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DatabaseInfo {
     pub id: String,
     pub path: String,
     pub key: String,
-    pub cipher_compatibility: i32,
+    /// Cipher parameters known to work for this database. `None` until a
+    /// connection attempt either confirms the caller-supplied
+    /// [`CipherParams`] or [`DatabaseManager::probe_cipher_params`] finds a
+    /// working profile; cached afterwards so later reconnects skip probing.
+    pub cipher_params: Option<CipherParams>,
     pub db_type: String,
     pub filename: String,
     pub size: Option<u64>,
@@ -39,6 +268,20 @@ pub struct QueryResult {
     pub total_rows: i64,
 }
 
+/// Output format for [`DatabaseManager::export_database`]. `PlaintextDb`
+/// writes a single unencrypted `.db` file to `out_path`; `Csv`/`Json` treat
+/// `out_path` as a directory and write one file per table.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ExportFormat {
+    PlaintextDb,
+    Csv,
+    Json,
+}
+
+/// Number of rows fetched per page when streaming a table out to CSV/JSON,
+/// mirroring the paging already used by [`DatabaseManager::query_table`].
+const EXPORT_PAGE_SIZE: i64 = 1000;
+
 #[derive(Debug, Error)]
 pub enum DatabaseError {
     #[error("Database connection error: {0}")]
@@ -59,16 +302,47 @@ pub enum DatabaseError {
 
 pub type DatabaseResult<T> = Result<T, DatabaseError>;
 
+/// Converts a single column value to JSON without lossy string coercion.
+/// BLOBs (WeChat stores compressed message payloads and thumbnails this
+/// way) are base64-encoded rather than dropped as `Null`.
+fn value_ref_to_json(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => serde_json::Value::Number(i.into()),
+        ValueRef::Real(f) => serde_json::Number::from_f64(f)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        ValueRef::Text(t) => serde_json::Value::String(String::from_utf8_lossy(t).into_owned()),
+        ValueRef::Blob(b) => {
+            let mut obj = serde_json::Map::new();
+            obj.insert("$blob".to_string(), serde_json::Value::String(base64::engine::general_purpose::STANDARD.encode(b)));
+            obj.insert("len".to_string(), serde_json::Value::Number((b.len() as i64).into()));
+            serde_json::Value::Object(obj)
+        }
+    }
+}
+
+/// Maps a whole row to JSON values, one per column, via [`value_ref_to_json`].
+fn row_to_json_values(row: &Row, column_count: usize) -> rusqlite::Result<Vec<serde_json::Value>> {
+    let mut values = Vec::with_capacity(column_count);
+    for i in 0..column_count {
+        values.push(value_ref_to_json(row.get_ref(i)?));
+    }
+    Ok(values)
+}
+
 pub struct DatabaseManager {
     databases: HashMap<String, DatabaseInfo>,
-    connections: HashMap<String, Connection>,
+    pools: HashMap<String, Pool<SqliteConnectionManager>>,
+    query_history: HashMap<String, Arc<Mutex<VecDeque<QueryHistoryEntry>>>>,
 }
 
 impl DatabaseManager {
     pub fn new() -> Self {
         Self {
             databases: HashMap::new(),
-            connections: HashMap::new(),
+            pools: HashMap::new(),
+            query_history: HashMap::new(),
         }
     }
 
@@ -141,7 +415,7 @@ impl DatabaseManager {
             id,
             path: path.to_string(),
             key: key.to_string(),
-            cipher_compatibility: 3,
+            cipher_params: None,
             db_type,
             filename,
             size,
@@ -203,76 +477,175 @@ impl DatabaseManager {
         self.databases.get(id)
     }
 
-    pub fn connect_database(&mut self, id: &str) -> DatabaseResult<()> {
+    /// Connects to a database and replaces its connection pool. A bare probe
+    /// connection is used first to confirm (or discover) the working cipher
+    /// profile, since that has to be known before the pool's
+    /// [`CipherCustomizer`] can be built; every connection the pool opens
+    /// afterwards gets the same PRAGMAs applied once, when it is created.
+    pub fn connect_database(&mut self, id: &str, opts: ConnectionOptions) -> DatabaseResult<()> {
         let db_info = self.databases.get(id)
             .ok_or_else(|| DatabaseError::NotFound(id.to_string()))?;
-        
+
         if !db_info.accessible {
             return Err(DatabaseError::AccessDenied(format!("Database {} is not accessible", id)));
         }
 
-        let conn = Connection::open(&db_info.path)
-            .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to open database file: {}", e)))?;
-        
-        // Helper function to execute PRAGMA with fallback for rusqlite quirks
-        let execute_pragma = |pragma: &str| -> Result<(), DatabaseError> {
-            match conn.execute(pragma, []) {
-                Ok(_) => Ok(()),
-                Err(rusqlite::Error::ExecuteReturnedResults) => {
-                    // Some PRAGMA statements might return results, consume them
-                    let mut stmt = conn.prepare(pragma)
-                        .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to prepare pragma: {}", e)))?;
-                    let mut rows = stmt.query([])
-                        .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to query pragma: {}", e)))?;
-                    while let Some(_) = rows.next()
-                        .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to read pragma results: {}", e)))? {
-                        // Consume any results
-                    }
-                    Ok(())
+        let path = db_info.path.clone();
+        let key = db_info.key.clone();
+        let already_probed = db_info.cipher_params.is_some();
+
+        let mut effective_opts = opts;
+        if let Some(cached) = &db_info.cipher_params {
+            effective_opts.cipher = cached.clone();
+        }
+
+        let conn = Self::open_with_cipher(&path, &key, &effective_opts)?;
+
+        match Self::test_sqlite_master(&conn) {
+            // The caller-supplied/default cipher params worked on the first
+            // try; cache them too so later reconnects skip probing, same as
+            // the fallback-probe arm below already does.
+            Ok(()) => {
+                if let Some(db) = self.databases.get_mut(id) {
+                    db.cipher_params = Some(effective_opts.cipher.clone());
                 }
-                Err(e) => Err(DatabaseError::ConnectionFailed(format!("Failed to execute pragma: {}", e))),
             }
-        };
-        
-        // Set SQLCipher compatibility mode first (for SQLCipher3)
-        execute_pragma("PRAGMA cipher_compatibility = 3")?;
-        
-        // Set SQLCipher key - use the key as-is from the .keys file (already in x'...' format)
-        execute_pragma(&format!("PRAGMA key = \"{}\"", db_info.key))?;
-        
-        // Set additional SQLCipher3 parameters for compatibility
-        execute_pragma("PRAGMA cipher_page_size = 1024")?;
-        execute_pragma("PRAGMA kdf_iter = 64000")?;
-        execute_pragma("PRAGMA cipher_hmac_algorithm = HMAC_SHA1")?;
-        execute_pragma("PRAGMA cipher_kdf_algorithm = PBKDF2_HMAC_SHA1")?;
-        
-        // Test connection by querying sqlite_master
-        {
-            let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type='table' LIMIT 1")
-                .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to prepare test query: {}", e)))?;
-            let _: Vec<String> = stmt.query_map([], |row| {
-                Ok(row.get::<_, String>(0)?)
-            })?.collect::<Result<Vec<_>, _>>()
-                .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to execute test query: {}", e)))?;
+            // The hardcoded/caller-supplied cipher params didn't work; fall
+            // back to probing the known WeChat profiles before giving up.
+            Err(_) if !already_probed => {
+                let profile = Self::probe_cipher_params(&path, &key)?;
+                effective_opts.cipher = profile.clone();
+                let conn = Self::open_with_cipher(&path, &key, &effective_opts)?;
+                Self::test_sqlite_master(&conn)?;
+
+                if let Some(db) = self.databases.get_mut(id) {
+                    db.cipher_params = Some(profile);
+                }
+            }
+            Err(e) => return Err(e),
         }
-        
-        self.connections.insert(id.to_string(), conn);
+
+        let history = self.query_history
+            .entry(id.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(VecDeque::with_capacity(QUERY_HISTORY_CAPACITY))))
+            .clone();
+
+        let manager = SqliteConnectionManager::file(&path);
+        let customizer = CipherCustomizer {
+            key,
+            opts: effective_opts,
+            db_id: id.to_string(),
+            history,
+        };
+        let pool = Pool::builder()
+            .connection_customizer(Box::new(customizer))
+            .build(manager)
+            .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to build connection pool: {}", e)))?;
+
+        self.pools.insert(id.to_string(), pool);
         Ok(())
     }
 
-    pub fn get_tables(&self, db_id: &str) -> DatabaseResult<Vec<TableInfo>> {
-        let conn = self.connections.get(db_id)
+    fn open_with_cipher(path: &str, key: &str, opts: &ConnectionOptions) -> DatabaseResult<Connection> {
+        let conn = Connection::open(path)
+            .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to open database file: {}", e)))?;
+        opts.apply(&conn, key)
+            .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to apply connection options: {}", e)))?;
+        Ok(conn)
+    }
+
+    /// Checks out a pooled, already-keyed connection for a connected database.
+    fn pooled_connection(&self, db_id: &str) -> DatabaseResult<r2d2::PooledConnection<SqliteConnectionManager>> {
+        let pool = self.pools.get(db_id)
             .ok_or_else(|| DatabaseError::NotFound(format!("Connection for database {} not found", db_id)))?;
-        
+        pool.get()
+            .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to check out pooled connection: {}", e)))
+    }
+
+    fn test_sqlite_master(conn: &Connection) -> DatabaseResult<()> {
+        let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type='table' LIMIT 1")
+            .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to prepare test query: {}", e)))?;
+        let _: Vec<String> = stmt.query_map([], |row| {
+            Ok(row.get::<_, String>(0)?)
+        })?.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to execute test query: {}", e)))?;
+        Ok(())
+    }
+
+    /// Known WeChat cipher profiles, tried in order by [`Self::probe_cipher_params`].
+    fn cipher_profile_candidates() -> Vec<CipherParams> {
+        vec![
+            CipherParams {
+                compatibility: 4,
+                page_size: 4096,
+                kdf_iter: 256000,
+                hmac_algorithm: "HMAC_SHA512".to_string(),
+                kdf_algorithm: "PBKDF2_HMAC_SHA512".to_string(),
+                plaintext_header_size: 0,
+            },
+            CipherParams {
+                compatibility: 3,
+                page_size: 1024,
+                kdf_iter: 64000,
+                hmac_algorithm: "HMAC_SHA1".to_string(),
+                kdf_algorithm: "PBKDF2_HMAC_SHA1".to_string(),
+                plaintext_header_size: 0,
+            },
+            CipherParams {
+                compatibility: 3,
+                page_size: 4096,
+                kdf_iter: 64000,
+                hmac_algorithm: "HMAC_SHA1".to_string(),
+                kdf_algorithm: "PBKDF2_HMAC_SHA1".to_string(),
+                plaintext_header_size: 0,
+            },
+            CipherParams {
+                compatibility: 1,
+                page_size: 1024,
+                kdf_iter: 4000,
+                hmac_algorithm: "HMAC_SHA1".to_string(),
+                kdf_algorithm: "PBKDF2_HMAC_SHA1".to_string(),
+                plaintext_header_size: 0,
+            },
+        ]
+    }
+
+    /// Tries each known WeChat cipher profile against a fresh connection
+    /// until one can read `sqlite_master`, so callers don't have to guess
+    /// the SQLCipher version, page size and KDF iteration count up front.
+    pub fn probe_cipher_params(path: &str, key: &str) -> DatabaseResult<CipherParams> {
+        for candidate in Self::cipher_profile_candidates() {
+            let opts = ConnectionOptions {
+                cipher: candidate.clone(),
+                ..Default::default()
+            };
+            let conn = match Self::open_with_cipher(path, key, &opts) {
+                Ok(conn) => conn,
+                Err(_) => continue,
+            };
+            if Self::test_sqlite_master(&conn).is_ok() {
+                return Ok(candidate);
+            }
+        }
+
+        Err(DatabaseError::ConnectionFailed(format!(
+            "No known SQLCipher profile could open {}",
+            path
+        )))
+    }
+
+    pub fn get_tables(&self, db_id: &str) -> DatabaseResult<Vec<TableInfo>> {
+        let conn = self.pooled_connection(db_id)?;
+
         let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type='table' ORDER BY name")?;
         let table_names: Vec<String> = stmt.query_map([], |row| {
             Ok(row.get::<_, String>(0)?)
         })?.collect::<Result<Vec<_>, _>>()?;
-        
+
         let mut tables = Vec::new();
         for table_name in table_names {
-            let columns = self.get_table_columns(conn, &table_name)?;
-            let row_count = self.get_table_row_count(conn, &table_name)?;
+            let columns = self.get_table_columns(&conn, &table_name)?;
+            let row_count = self.get_table_row_count(&conn, &table_name)?;
             
             tables.push(TableInfo {
                 name: table_name,
@@ -305,9 +678,8 @@ impl DatabaseManager {
     }
 
     pub fn query_table(&self, db_id: &str, table_name: &str, limit: Option<i64>, offset: Option<i64>) -> DatabaseResult<QueryResult> {
-        let conn = self.connections.get(db_id)
-            .ok_or_else(|| DatabaseError::NotFound(format!("Connection for database {} not found", db_id)))?;
-        
+        let conn = self.pooled_connection(db_id)?;
+
         let limit_clause = limit.map(|l| format!(" LIMIT {}", l)).unwrap_or_default();
         let offset_clause = offset.map(|o| format!(" OFFSET {}", o)).unwrap_or_default();
         
@@ -321,29 +693,11 @@ impl DatabaseManager {
         }
         
         let rows: Vec<Vec<serde_json::Value>> = stmt.query_map([], |row| {
-            let mut values = Vec::new();
-            for i in 0..column_count {
-                let value = match row.get::<_, Option<String>>(i) {
-                    Ok(Some(s)) => serde_json::Value::String(s),
-                    Ok(None) => serde_json::Value::Null,
-                    Err(_) => {
-                        // Try other types
-                        if let Ok(n) = row.get::<_, i64>(i) {
-                            serde_json::Value::Number(n.into())
-                        } else if let Ok(f) = row.get::<_, f64>(i) {
-                            serde_json::Value::Number(serde_json::Number::from_f64(f).unwrap_or(serde_json::Number::from(0)))
-                        } else {
-                            serde_json::Value::Null
-                        }
-                    }
-                };
-                values.push(value);
-            }
-            Ok(values)
+            row_to_json_values(row, column_count)
         })?.collect::<Result<Vec<_>, _>>()?;
-        
-        let total_rows = self.get_table_row_count(conn, table_name)?;
-        
+
+        let total_rows = self.get_table_row_count(&conn, table_name)?;
+
         Ok(QueryResult {
             columns,
             rows,
@@ -351,41 +705,56 @@ impl DatabaseManager {
         })
     }
 
-    pub fn execute_query(&self, db_id: &str, query: &str) -> DatabaseResult<QueryResult> {
-        let conn = self.connections.get(db_id)
-            .ok_or_else(|| DatabaseError::NotFound(format!("Connection for database {} not found", db_id)))?;
-        
+    pub fn execute_query(&self, db_id: &str, query: &str, read_only: bool) -> DatabaseResult<QueryResult> {
+        if read_only && !is_read_only_sql(query) {
+            return Err(DatabaseError::AccessDenied(
+                "Query rejected: read_only mode forbids write statements".to_string(),
+            ));
+        }
+
+        let conn = self.pooled_connection(db_id)?;
+        let result = Self::run_query(&conn, query)?;
+        self.record_row_count(db_id, result.total_rows);
+        Ok(result)
+    }
+
+    /// Fills in `row_count` on the history entry this thread's own query
+    /// just produced, since the trace callback itself has no visibility
+    /// into how many rows a statement returned. Correlates by the id the
+    /// trace callback stashed in [`LAST_TRACED_ENTRY_ID`] rather than
+    /// assuming it's `back()` of the ring buffer — concurrent
+    /// `execute_query` calls against the same database share one buffer, so
+    /// another thread's entry can land on top of this one in the meantime.
+    fn record_row_count(&self, db_id: &str, row_count: i64) {
+        let Some(id) = LAST_TRACED_ENTRY_ID.with(|cell| cell.take()) else {
+            return;
+        };
+        if let Some(history) = self.query_history.get(db_id) {
+            let mut buf = history.lock().unwrap();
+            if let Some(entry) = buf.iter_mut().rev().find(|e| e.id == id) {
+                entry.row_count = Some(row_count);
+            }
+        }
+    }
+
+    /// Core SQL-running logic shared by [`Self::execute_query`] and
+    /// [`Self::federated_query`] — both just differ in how they obtain the
+    /// connection the query runs against.
+    fn run_query(conn: &Connection, query: &str) -> DatabaseResult<QueryResult> {
         let mut stmt = conn.prepare(query)?;
-        
+
         let column_count = stmt.column_count();
         let mut columns = Vec::new();
         for i in 0..column_count {
             columns.push(stmt.column_name(i)?.to_string());
         }
-        
+
         let rows: Vec<Vec<serde_json::Value>> = stmt.query_map([], |row| {
-            let mut values = Vec::new();
-            for i in 0..column_count {
-                let value = match row.get::<_, Option<String>>(i) {
-                    Ok(Some(s)) => serde_json::Value::String(s),
-                    Ok(None) => serde_json::Value::Null,
-                    Err(_) => {
-                        if let Ok(n) = row.get::<_, i64>(i) {
-                            serde_json::Value::Number(n.into())
-                        } else if let Ok(f) = row.get::<_, f64>(i) {
-                            serde_json::Value::Number(serde_json::Number::from_f64(f).unwrap_or(serde_json::Number::from(0)))
-                        } else {
-                            serde_json::Value::Null
-                        }
-                    }
-                };
-                values.push(value);
-            }
-            Ok(values)
+            row_to_json_values(row, column_count)
         })?.collect::<Result<Vec<_>, _>>()?;
-        
+
         let total_rows = rows.len() as i64;
-        
+
         Ok(QueryResult {
             columns,
             rows,
@@ -393,12 +762,242 @@ impl DatabaseManager {
         })
     }
 
+    /// Opens a connection to `db_ids[0]` and `ATTACH`es the remaining
+    /// databases under generated aliases (`federated_0`, `federated_1`, ...),
+    /// each re-keyed with its own cipher params, so `query` can join across
+    /// them via `alias.Table` — e.g. to reconstruct a conversation timeline
+    /// spread across WeChat's per-contact `MSG*.db` shards.
+    pub fn federated_query(&self, db_ids: &[String], query: &str) -> DatabaseResult<QueryResult> {
+        let (primary_id, rest) = db_ids.split_first()
+            .ok_or_else(|| DatabaseError::NotFound("federated_query requires at least one database".to_string()))?;
+
+        let mut conn = self.pooled_connection(primary_id)?;
+
+        // The ATTACH ... KEY ? statements below carry each shard's raw
+        // decryption key as a bound parameter, and rusqlite's trace API
+        // reports SQL with bound parameters already expanded — so tracing
+        // must be off while we attach, or the keys end up verbatim in the
+        // query history ring buffer that get_query_history hands to the
+        // frontend. Re-installed before the actual query runs so that one
+        // is still recorded as usual.
+        conn.trace(None);
+
+        let mut attached = Vec::new();
+        let attach_result: DatabaseResult<()> = (|| {
+            for (i, db_id) in rest.iter().enumerate() {
+                let alias = format!("federated_{}", i);
+                self.attach_database(&conn, db_id, &alias)?;
+                attached.push(alias);
+            }
+            Ok(())
+        })();
+
+        self.install_trace(&mut conn, primary_id);
+
+        let query_result = attach_result.and_then(|_| Self::run_query(&conn, query));
+
+        for alias in &attached {
+            let _ = conn.execute(&format!("DETACH DATABASE {}", alias), []);
+        }
+
+        query_result
+    }
+
+    /// (Re-)installs the trace callback that records statements run on
+    /// `conn` into `db_id`'s query history ring buffer. Used after
+    /// federated attaches temporarily disable tracing.
+    fn install_trace(&self, conn: &mut Connection, db_id: &str) {
+        if let Some(history) = self.query_history.get(db_id) {
+            conn.trace(Some(make_trace_callback(db_id.to_string(), Arc::clone(history))));
+        }
+    }
+
+    /// Attaches `db_id`'s file under `alias`. Its cipher params are pushed
+    /// via `PRAGMA cipher_default_*` *before* the `ATTACH`, since SQLCipher
+    /// derives an attached database's key at attach time from whatever the
+    /// current cipher defaults are — schema-qualified PRAGMAs applied after
+    /// the fact (`alias.cipher_page_size`, ...) only affect pages written
+    /// afterwards, too late to matter for key derivation.
+    fn attach_database(&self, conn: &Connection, db_id: &str, alias: &str) -> DatabaseResult<()> {
+        let db_info = self.databases.get(db_id)
+            .ok_or_else(|| DatabaseError::NotFound(db_id.to_string()))?;
+
+        let cipher = match &db_info.cipher_params {
+            Some(cipher) => cipher.clone(),
+            // Not connected yet (or connected before its working profile was
+            // cached) — discover it rather than silently falling back to
+            // defaults, which would derive the wrong key for any non-default
+            // shard (e.g. a SQLCipher-4 shard attached next to a compat-3
+            // primary, exactly the multi-version case this feature is for).
+            None => Self::probe_cipher_params(&db_info.path, &db_info.key)?,
+        };
+        // Only the cipher_default_* family is used here, not the standalone
+        // `cipher_compatibility` PRAGMA: that one targets the *current* db
+        // context (the primary, already keyed) rather than the database
+        // about to be attached, so it wouldn't reliably affect this shard's
+        // key derivation. page_size/kdf_iter/hmac/kdf fully specify the
+        // derivation on their own; compatibility is just a named preset of
+        // those same four values, so nothing is lost by dropping it here.
+        conn.execute(&format!("PRAGMA cipher_default_page_size = {}", cipher.page_size), [])?;
+        conn.execute(&format!("PRAGMA cipher_default_kdf_iter = {}", cipher.kdf_iter), [])?;
+        conn.execute(&format!("PRAGMA cipher_default_hmac_algorithm = {}", cipher.hmac_algorithm), [])?;
+        conn.execute(&format!("PRAGMA cipher_default_kdf_algorithm = {}", cipher.kdf_algorithm), [])?;
+        if cipher.plaintext_header_size > 0 {
+            conn.execute(&format!("PRAGMA cipher_default_plaintext_header_size = {}", cipher.plaintext_header_size), [])?;
+        }
+
+        let attach_sql = format!("ATTACH DATABASE ? AS {} KEY ?", alias);
+        conn.execute(&attach_sql, rusqlite::params![db_info.path, db_info.key])?;
+
+        Ok(())
+    }
+
+    pub fn export_database(&self, db_id: &str, out_path: &str, format: ExportFormat) -> DatabaseResult<()> {
+        match format {
+            ExportFormat::PlaintextDb => self.export_plaintext_db(db_id, out_path),
+            ExportFormat::Csv => self.export_tables_csv(db_id, out_path),
+            ExportFormat::Json => self.export_tables_json(db_id, out_path),
+        }
+    }
+
+    /// Uses SQLCipher's `sqlcipher_export` to write a standard, unencrypted
+    /// SQLite copy of the live decrypted connection that any SQLite viewer
+    /// can open directly.
+    fn export_plaintext_db(&self, db_id: &str, out_path: &str) -> DatabaseResult<()> {
+        let conn = self.pooled_connection(db_id)?;
+
+        conn.execute("ATTACH DATABASE ? AS plaintext KEY ''", [out_path])
+            .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to attach export target: {}", e)))?;
+
+        let export_result: DatabaseResult<()> = (|| {
+            let mut stmt = conn.prepare("SELECT sqlcipher_export('plaintext')")?;
+            let mut rows = stmt.query([])?;
+            while rows.next()?.is_some() {
+                // sqlcipher_export() returns one row per copied table; we
+                // don't need the details, just that it ran to completion.
+            }
+            Ok(())
+        })();
+
+        conn.execute("DETACH DATABASE plaintext", [])
+            .map_err(|e| DatabaseError::ConnectionFailed(format!("Failed to detach export target: {}", e)))?;
+
+        export_result
+    }
+
+    fn export_tables_csv(&self, db_id: &str, out_dir: &str) -> DatabaseResult<()> {
+        std::fs::create_dir_all(out_dir)?;
+
+        for table in self.get_tables(db_id)? {
+            let file_path = Path::new(out_dir).join(format!("{}.csv", table.name));
+            let file = std::fs::File::create(&file_path)?;
+            let mut writer = std::io::BufWriter::new(file);
+            use std::io::Write;
+
+            writeln!(writer, "{}", table.columns.iter().map(|c| c.name.as_str()).collect::<Vec<_>>().join(","))?;
+
+            let mut offset = 0i64;
+            loop {
+                let page = self.query_table(db_id, &table.name, Some(EXPORT_PAGE_SIZE), Some(offset))?;
+                if page.rows.is_empty() {
+                    break;
+                }
+                let page_len = page.rows.len();
+                for row in page.rows {
+                    let line = row.iter().map(Self::csv_cell).collect::<Vec<_>>().join(",");
+                    writeln!(writer, "{}", line)?;
+                }
+                if page_len < EXPORT_PAGE_SIZE as usize {
+                    break;
+                }
+                offset += EXPORT_PAGE_SIZE;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn csv_cell(value: &serde_json::Value) -> String {
+        let raw = match value {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        if raw.contains(',') || raw.contains('"') || raw.contains('\n') {
+            format!("\"{}\"", raw.replace('"', "\"\""))
+        } else {
+            raw
+        }
+    }
+
+    /// Streams each table into a `[...]`-wrapped JSON array one page at a
+    /// time, like [`Self::export_tables_csv`] does for CSV, rather than
+    /// accumulating every row in memory — WeChat message tables can run into
+    /// the hundreds of thousands of BLOB-bearing rows.
+    fn export_tables_json(&self, db_id: &str, out_dir: &str) -> DatabaseResult<()> {
+        std::fs::create_dir_all(out_dir)?;
+        use std::io::Write;
+
+        for table in self.get_tables(db_id)? {
+            let file_path = Path::new(out_dir).join(format!("{}.json", table.name));
+            let file = std::fs::File::create(&file_path)?;
+            let mut writer = std::io::BufWriter::new(file);
+
+            writer.write_all(b"[")?;
+
+            let mut offset = 0i64;
+            let mut wrote_any = false;
+            loop {
+                let page = self.query_table(db_id, &table.name, Some(EXPORT_PAGE_SIZE), Some(offset))?;
+                if page.rows.is_empty() {
+                    break;
+                }
+                let page_len = page.rows.len();
+                for row in page.rows {
+                    let obj: serde_json::Map<String, serde_json::Value> =
+                        page.columns.iter().cloned().zip(row).collect();
+                    if wrote_any {
+                        writer.write_all(b",")?;
+                    }
+                    serde_json::to_writer(&mut writer, &serde_json::Value::Object(obj))
+                        .map_err(|e| DatabaseError::Parse(e.to_string()))?;
+                    wrote_any = true;
+                }
+                if page_len < EXPORT_PAGE_SIZE as usize {
+                    break;
+                }
+                offset += EXPORT_PAGE_SIZE;
+            }
+
+            writer.write_all(b"]")?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns up to `limit` most recent traced statements for a database,
+    /// oldest first. `None` returns the whole ring buffer.
+    pub fn get_query_history(&self, db_id: &str, limit: Option<usize>) -> Vec<QueryHistoryEntry> {
+        let Some(history) = self.query_history.get(db_id) else {
+            return Vec::new();
+        };
+        let buf = history.lock().unwrap();
+        let limit = limit.unwrap_or(buf.len());
+        buf.iter().rev().take(limit).rev().cloned().collect()
+    }
+
+    pub fn clear_query_history(&self, db_id: &str) {
+        if let Some(history) = self.query_history.get(db_id) {
+            history.lock().unwrap().clear();
+        }
+    }
+
     pub fn disconnect_database(&mut self, id: &str) -> DatabaseResult<()> {
-        self.connections.remove(id);
+        self.pools.remove(id);
         Ok(())
     }
 
     pub fn disconnect_all(&mut self) {
-        self.connections.clear();
+        self.pools.clear();
     }
 }
\ No newline at end of file
@@ -0,0 +1,15 @@
+use crate::database::ExportFormat;
+use crate::DbManager;
+use tauri::State;
+
+#[tauri::command]
+pub fn export_database(
+    db_id: String,
+    out_path: String,
+    format: ExportFormat,
+    manager: State<DbManager>,
+) -> Result<(), String> {
+    let mgr = manager.read().unwrap();
+    mgr.export_database(&db_id, &out_path, format)
+        .map_err(|e| format!("Failed to export database: {}", e))
+}
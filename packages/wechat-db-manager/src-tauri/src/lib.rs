@@ -1,12 +1,18 @@
 mod database;
+mod export;
 
 use database::{
-    DatabaseManager, DatabaseInfo, TableInfo, QueryResult
+    DatabaseManager, DatabaseInfo, TableInfo, QueryResult, ConnectionOptions, QueryHistoryEntry
 };
-use std::sync::Mutex;
+use export::export_database;
+use std::sync::RwLock;
 use tauri::State;
 
-type DbManager = Mutex<DatabaseManager>;
+// A `RwLock` (rather than a `Mutex`) so read-only commands (queries,
+// table listing, history) can run concurrently instead of serializing
+// behind one global lock — the whole point of pooling connections per
+// database in the first place.
+pub type DbManager = RwLock<DatabaseManager>;
 
 #[tauri::command]
 fn greet(name: &str) -> String {
@@ -18,7 +24,7 @@ fn load_keys_file(path: String, manager: State<DbManager>) -> Result<Vec<Databas
     let databases = DatabaseManager::parse_keys_file(&path)
         .map_err(|e| format!("Failed to parse keys file: {}", e))?;
     
-    let mut mgr = manager.lock().unwrap();
+    let mut mgr = manager.write().unwrap();
     mgr.load_databases(databases.clone());
     
     Ok(databases)
@@ -26,20 +32,24 @@ fn load_keys_file(path: String, manager: State<DbManager>) -> Result<Vec<Databas
 
 #[tauri::command]
 fn get_databases(manager: State<DbManager>) -> Result<Vec<DatabaseInfo>, String> {
-    let mgr = manager.lock().unwrap();
+    let mgr = manager.read().unwrap();
     Ok(mgr.get_databases())
 }
 
 #[tauri::command]
-fn connect_database(db_id: String, manager: State<DbManager>) -> Result<(), String> {
-    let mut mgr = manager.lock().unwrap();
-    mgr.connect_database(&db_id)
+fn connect_database(
+    db_id: String,
+    options: Option<ConnectionOptions>,
+    manager: State<DbManager>
+) -> Result<(), String> {
+    let mut mgr = manager.write().unwrap();
+    mgr.connect_database(&db_id, options.unwrap_or_default())
         .map_err(|e| format!("Failed to connect to database: {}", e))
 }
 
 #[tauri::command]
 fn get_tables(db_id: String, manager: State<DbManager>) -> Result<Vec<TableInfo>, String> {
-    let mgr = manager.lock().unwrap();
+    let mgr = manager.read().unwrap();
     mgr.get_tables(&db_id)
         .map_err(|e| format!("Failed to get tables: {}", e))
 }
@@ -52,7 +62,7 @@ fn query_table(
     offset: Option<i64>,
     manager: State<DbManager>
 ) -> Result<QueryResult, String> {
-    let mgr = manager.lock().unwrap();
+    let mgr = manager.read().unwrap();
     mgr.query_table(&db_id, &table_name, limit, offset)
         .map_err(|e| format!("Failed to query table: {}", e))
 }
@@ -61,16 +71,45 @@ fn query_table(
 fn execute_query(
     db_id: String,
     query: String,
+    read_only: Option<bool>,
     manager: State<DbManager>
 ) -> Result<QueryResult, String> {
-    let mgr = manager.lock().unwrap();
-    mgr.execute_query(&db_id, &query)
+    let mgr = manager.read().unwrap();
+    mgr.execute_query(&db_id, &query, read_only.unwrap_or(false))
         .map_err(|e| format!("Failed to execute query: {}", e))
 }
 
+#[tauri::command]
+fn get_query_history(
+    db_id: String,
+    limit: Option<usize>,
+    manager: State<DbManager>
+) -> Result<Vec<QueryHistoryEntry>, String> {
+    let mgr = manager.read().unwrap();
+    Ok(mgr.get_query_history(&db_id, limit))
+}
+
+#[tauri::command]
+fn clear_query_history(db_id: String, manager: State<DbManager>) -> Result<(), String> {
+    let mgr = manager.read().unwrap();
+    mgr.clear_query_history(&db_id);
+    Ok(())
+}
+
+#[tauri::command]
+fn federated_query(
+    db_ids: Vec<String>,
+    query: String,
+    manager: State<DbManager>
+) -> Result<QueryResult, String> {
+    let mgr = manager.read().unwrap();
+    mgr.federated_query(&db_ids, &query)
+        .map_err(|e| format!("Failed to execute federated query: {}", e))
+}
+
 #[tauri::command]
 fn disconnect_database(db_id: String, manager: State<DbManager>) -> Result<(), String> {
-    let mut mgr = manager.lock().unwrap();
+    let mut mgr = manager.write().unwrap();
     mgr.disconnect_database(&db_id)
         .map_err(|e| format!("Failed to disconnect database: {}", e))
 }
@@ -89,6 +128,10 @@ pub fn run() {
             get_tables,
             query_table,
             execute_query,
+            get_query_history,
+            clear_query_history,
+            federated_query,
+            export_database,
             disconnect_database
         ])
         .run(tauri::generate_context!())